@@ -0,0 +1,155 @@
+use std::{collections::HashMap, env, sync::Mutex};
+
+use clap::Parser;
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    room::{Joined, Room},
+    ruma::{EventId, OwnedEventId, OwnedRoomId},
+};
+use tracing::error;
+
+use crate::state_event::{self, ChatGptConfigEventContent};
+
+/// In-room commands that set per-room ChatGPT overrides or generate an image
+/// instead of being forwarded to `room_event_to_chatgpt_request`.
+#[derive(Debug, Parser)]
+#[command(name = "", no_binary_name = true, disable_help_subcommand = true)]
+pub enum Command {
+    /// Switch the OpenAI model used for this room, e.g. `!model gpt-4`.
+    Model { name: String },
+    /// Set the sampling temperature used for this room, e.g. `!temperature 0.7`.
+    Temperature { value: f32 },
+    /// Set a persistent system prompt used for this room.
+    System {
+        #[arg(trailing_var_arg = true, num_args = 1..)]
+        prompt: Vec<String>,
+    },
+    /// Forget the conversation so far and start fresh from this point.
+    Reset,
+    /// Generate an image with DALL·E, e.g. `!image a corgi wearing a crown`.
+    Image {
+        #[arg(trailing_var_arg = true, num_args = 1..)]
+        prompt: Vec<String>,
+    },
+}
+
+/// What should happen as a result of a parsed in-room command.
+pub enum CommandOutcome {
+    /// Send this back to the room as a plain-text confirmation.
+    Reply(String),
+    /// Generate and upload an image for the given prompt.
+    GenerateImage(String),
+}
+
+/// Per-room overrides of the hard-coded `CreateChatCompletionRequest` fields.
+#[derive(Debug, Clone, Default)]
+pub struct RoomSettings {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub system_prompt: Option<String>,
+    /// History gathering should ignore every message up to and including this one.
+    pub reset_after: Option<OwnedEventId>,
+}
+
+lazy_static! {
+    static ref ROOM_SETTINGS: Mutex<HashMap<OwnedRoomId, RoomSettings>> =
+        Mutex::new(HashMap::new());
+    static ref COMMAND_PREFIX: String =
+        env::var("COMMAND_PREFIX").unwrap_or_else(|_| "!".to_string());
+}
+
+/// Returns a copy of the current settings for `room`, loading them from the
+/// room's persisted `rs.matrix-chatgpt.config` state event on first access.
+pub async fn room_settings(room: &Room) -> RoomSettings {
+    if let Some(settings) = ROOM_SETTINGS.lock().unwrap().get(room.room_id()) {
+        return settings.clone();
+    }
+
+    let settings = state_event::load_room_config(room)
+        .await
+        .map(|config| RoomSettings {
+            model: config.model,
+            temperature: config.temperature,
+            system_prompt: config.system_prompt,
+            reset_after: None,
+        })
+        .unwrap_or_default();
+
+    ROOM_SETTINGS
+        .lock()
+        .unwrap()
+        .insert(room.room_id().to_owned(), settings.clone());
+
+    settings
+}
+
+/// Parses `body` as an in-room command if it starts with the configured
+/// prefix. `!image` is returned as [`CommandOutcome::GenerateImage`] without
+/// touching any settings; every other command is applied to `room`'s
+/// settings and returned as a confirmation message to send back. Returns
+/// `None` if `body` doesn't start with the prefix or doesn't parse as a
+/// command, in which case the caller should forward it to ChatGPT as usual.
+pub async fn handle_command(
+    body: &str,
+    room: &Joined,
+    event_id: &EventId,
+) -> Option<CommandOutcome> {
+    let rest = body.strip_prefix(COMMAND_PREFIX.as_str())?;
+    let args = shell_words::split(rest).ok()?;
+    let command = Command::try_parse_from(args).ok()?;
+
+    if let Command::Image { prompt } = command {
+        return Some(CommandOutcome::GenerateImage(prompt.join(" ")));
+    }
+
+    let persist = !matches!(command, Command::Reset);
+
+    // Seed the in-memory cache from the persisted state event first, the same
+    // way the read path does. Otherwise a command issued before any normal
+    // chat message starts from `RoomSettings::default()` and, for a
+    // persisting command, immediately overwrites the room's saved config
+    // with that blank slate.
+    room_settings(&Room::Joined(room.clone())).await;
+
+    let (reply, settings) = {
+        let mut all_settings = ROOM_SETTINGS.lock().unwrap();
+        let settings = all_settings.entry(room.room_id().to_owned()).or_default();
+
+        let reply = match command {
+            Command::Model { name } => {
+                settings.model = Some(name.clone());
+                format!("Model set to `{name}` for this room.")
+            }
+            Command::Temperature { value } => {
+                settings.temperature = Some(value);
+                format!("Temperature set to `{value}` for this room.")
+            }
+            Command::System { prompt } => {
+                let prompt = prompt.join(" ");
+                settings.system_prompt = Some(prompt.clone());
+                format!("System prompt set to: {prompt}")
+            }
+            Command::Reset => {
+                settings.reset_after = Some(event_id.to_owned());
+                "Conversation history has been reset.".to_string()
+            }
+            Command::Image { .. } => unreachable!("handled above"),
+        };
+
+        (reply, settings.clone())
+    };
+
+    if persist {
+        let config = ChatGptConfigEventContent {
+            system_prompt: settings.system_prompt,
+            model: settings.model,
+            temperature: settings.temperature,
+        };
+
+        if let Err(err) = room.send_state_event(config).await {
+            error!("Failed to persist room config state event: {:?}", err);
+        }
+    }
+
+    Some(CommandOutcome::Reply(reply))
+}
@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use async_openai::types::{CreateImageRequestArgs, ImageResponseFormat, ImageSize};
+use matrix_sdk::{
+    room::Joined,
+    ruma::events::room::{
+        message::{ImageMessageEventContent, MessageType, RoomMessageEventContent},
+        ImageInfo,
+    },
+};
+
+use crate::OPENAI_CLIENT;
+
+/// Pixel dimensions requested from OpenAI via `ImageSize::S512x512` below;
+/// kept in sync with that choice so the `m.image` event reports correct
+/// dimensions without re-decoding the PNG.
+const IMAGE_SIZE_PX: u64 = 512;
+
+/// Handles `!image <prompt>`: generates an image with DALL·E, uploads it to
+/// the homeserver's media repo, and sends it as an `m.image` message.
+pub async fn generate_and_send(prompt: &str, room: &Joined) -> Result<()> {
+    let request = CreateImageRequestArgs::default()
+        .prompt(prompt)
+        .n(1)
+        .size(ImageSize::S512x512)
+        .response_format(ImageResponseFormat::Url)
+        .build()?;
+
+    let image_response = OPENAI_CLIENT.images().create(request).await?;
+    let image_url = &image_response
+        .data
+        .first()
+        .context("OpenAI returned no images")?
+        .url;
+
+    let image_bytes = reqwest::get(image_url).await?.bytes().await?;
+
+    let upload = room
+        .client()
+        .media()
+        .upload(&mime::IMAGE_PNG, &image_bytes)
+        .await?;
+
+    let mut info = ImageInfo::new();
+    info.mimetype = Some(mime::IMAGE_PNG.to_string());
+    info.size = matrix_sdk::ruma::UInt::new(image_bytes.len() as u64);
+    info.width = matrix_sdk::ruma::UInt::new(IMAGE_SIZE_PX);
+    info.height = matrix_sdk::ruma::UInt::new(IMAGE_SIZE_PX);
+
+    let content = RoomMessageEventContent::new(MessageType::Image(ImageMessageEventContent::plain(
+        prompt.to_string(),
+        upload.content_uri,
+        Some(Box::new(info)),
+    )));
+
+    room.send(content, None).await?;
+
+    Ok(())
+}
@@ -6,22 +6,38 @@ use async_openai::{
 use lazy_static::lazy_static;
 use matrix_sdk::{
     config::SyncSettings,
-    room::{MessagesOptions, Room},
+    room::{Joined, MessagesOptions, Room},
     ruma::{
         events::{
             room::{
                 member::StrippedRoomMemberEvent,
-                message::{MessageType, RoomMessageEventContent, SyncRoomMessageEvent},
+                message::{
+                    MessageType, Relation, Replacement, RoomMessageEventContent,
+                    SyncRoomMessageEvent,
+                },
             },
             AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent, OriginalSyncMessageLikeEvent,
         },
-        UserId,
+        OwnedEventId, UserId,
     },
     Client as MatrixClient,
 };
-use std::{env, time::Duration};
+use commands::CommandOutcome;
+use futures_util::StreamExt;
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, Instant},
+};
 use tracing::{debug, error, info};
 
+mod commands;
+mod image;
+mod session;
+mod state_event;
+mod tokens;
+mod verification;
+
 lazy_static! {
     static ref OPENAI_CLIENT: OpenAIClient = {
         let openai_api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
@@ -44,24 +60,80 @@ lazy_static! {
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    let matrix_username = env::var("MATRIX_USERNAME").expect("MATRIX_USERNAME must be set");
-    let matrix_password = env::var("MATRIX_PASSWORD").expect("MATRIX_PASSWORD must be set");
-    let matrix_user_id = UserId::parse(matrix_username)?;
+    let session_path =
+        env::var("SESSION_PATH").unwrap_or_else(|_| "matrix_session.toml".to_string());
+    let crypto_store_path =
+        env::var("CRYPTO_STORE_PATH").unwrap_or_else(|_| "matrix_crypto_store".to_string());
+    let crypto_store_passphrase = env::var("CRYPTO_STORE_PASSPHRASE").ok();
+
+    let matrix_client = if let Some(stored_session) = session::load_session(&session_path).await? {
+        info!("Restoring previous session from {}", session_path);
+
+        let matrix_client = MatrixClient::builder()
+            .server_name(stored_session.user_id.server_name())
+            .respect_login_well_known(true)
+            .handle_refresh_tokens()
+            .sqlite_store(&crypto_store_path, crypto_store_passphrase.as_deref())
+            .build()
+            .await?;
+
+        matrix_client.restore_login(stored_session).await?;
+
+        matrix_client
+    } else {
+        info!("No previous session found, logging in with username and password");
+
+        let matrix_username = env::var("MATRIX_USERNAME").expect("MATRIX_USERNAME must be set");
+        let matrix_password = env::var("MATRIX_PASSWORD").expect("MATRIX_PASSWORD must be set");
+        let matrix_user_id = UserId::parse(matrix_username)?;
+
+        let matrix_client = MatrixClient::builder()
+            .server_name(matrix_user_id.server_name())
+            .respect_login_well_known(true)
+            .handle_refresh_tokens()
+            .sqlite_store(&crypto_store_path, crypto_store_passphrase.as_deref())
+            .build()
+            .await?;
+
+        matrix_client
+            .login_username(&matrix_user_id, &matrix_password)
+            .initial_device_display_name("matrix-chatgpt")
+            .send()
+            .await?;
+
+        let new_session = matrix_client
+            .session()
+            .expect("client should have a session right after logging in");
+        session::save_session(&session_path, new_session).await?;
+
+        matrix_client
+    };
+
+    // `.handle_refresh_tokens()` means the access/refresh token pair can
+    // rotate at any point during the run; keep the on-disk session current so
+    // a restart never tries to restore with an already-invalidated token.
+    tokio::spawn({
+        let matrix_client = matrix_client.clone();
+        let session_path = session_path.clone();
 
-    let matrix_client = MatrixClient::builder()
-        .server_name(matrix_user_id.server_name())
-        .respect_login_well_known(true)
-        .handle_refresh_tokens()
-        .build()
-        .await?;
+        async move {
+            let mut session_changes = matrix_client.subscribe_to_session_changes();
 
-    matrix_client
-        .login_username(&matrix_user_id, &matrix_password)
-        .initial_device_display_name("matrix-chatgpt")
-        .send()
-        .await?;
+            while session_changes.recv().await.is_ok() {
+                let Some(session) = matrix_client.session() else {
+                    continue;
+                };
+
+                if let Err(err) = session::save_session(&session_path, session).await {
+                    error!("Failed to persist refreshed session: {:?}", err);
+                }
+            }
+        }
+    });
 
     matrix_client.add_event_handler(on_stripped_state_member);
+    matrix_client.add_event_handler(verification::on_device_key_verification_request);
+    matrix_client.add_event_handler(verification::on_device_key_verification_start);
 
     // An initial sync to set up state and so our bot doesn't respond to old messages.
     let sync_token = matrix_client
@@ -111,6 +183,28 @@ async fn on_room_message(event: SyncRoomMessageEvent, room: Room, client: Matrix
         })
         .ok();
 
+    if let MessageType::Text(ref text_content) = event.content.msgtype {
+        match commands::handle_command(&text_content.body, joined_room, &event.event_id).await {
+            Some(CommandOutcome::Reply(reply)) => {
+                joined_room
+                    .send(RoomMessageEventContent::text_plain(reply), None)
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to send command confirmation: {:?}", e);
+                    })
+                    .ok();
+                return;
+            }
+            Some(CommandOutcome::GenerateImage(prompt)) => {
+                if let Err(err) = image::generate_and_send(&prompt, joined_room).await {
+                    error!("Failed to generate image for prompt {:?}: {:?}", prompt, err);
+                }
+                return;
+            }
+            None => {}
+        }
+    }
+
     joined_room
         .typing_notice(true)
         .await
@@ -119,24 +213,73 @@ async fn on_room_message(event: SyncRoomMessageEvent, room: Room, client: Matrix
         })
         .ok();
 
-    let Ok(chatgpt_request) = room_event_to_chatgpt_request(event, &room, &client).await else {
+    let Ok(mut chatgpt_request) = room_event_to_chatgpt_request(event, &room, &client).await
+    else {
         return;
     };
-    let Ok(chatgpt_response) = Chat::new(&OPENAI_CLIENT).create(chatgpt_request).await else { return; };
+    chatgpt_request.stream = Some(true);
+
+    let Ok(placeholder) = joined_room
+        .send(RoomMessageEventContent::text_markdown("…"), None)
+        .await
+        .map_err(|e| error!("Failed to send placeholder message: {:?}", e))
+    else {
+        return;
+    };
+
+    let Ok(mut chatgpt_stream) = Chat::new(&OPENAI_CLIENT)
+        .create_stream(chatgpt_request)
+        .await
+        .map_err(|e| error!("Failed to start ChatGPT stream: {:?}", e))
+    else {
+        return;
+    };
+
+    let mut buffer = String::new();
+    let mut last_edit = Instant::now();
+
+    while let Some(delta) = chatgpt_stream.next().await {
+        let Ok(delta) = delta.map_err(|e| error!("Error while streaming ChatGPT response: {:?}", e)) else {
+            break;
+        };
+
+        if let Some(content) = delta.choices.get(0).and_then(|choice| choice.delta.content.clone()) {
+            buffer.push_str(&content);
+        }
 
-    let response = chatgpt_response.choices[0].message.content.clone();
+        if last_edit.elapsed() >= Duration::from_millis(500) {
+            send_edit(joined_room, &placeholder.event_id, &buffer).await;
+            last_edit = Instant::now();
+        }
+    }
 
-    debug!("Sending ChatGPT response: {}", response);
+    debug!("Finished streaming ChatGPT response: {}", buffer);
+
+    send_edit(joined_room, &placeholder.event_id, &buffer).await;
 
     joined_room
-        .send(RoomMessageEventContent::text_markdown(response), None)
+        .typing_notice(false)
         .await
         .map_err(|e| {
-            error!("Failed to send answer: {:?}", e);
+            error!("Failed to clear typing notice: {:?}", e);
         })
         .ok();
 }
 
+/// Sends an `m.replace` edit of `event_id` so the room sees `body` as the
+/// message's current content, used to progressively reveal streamed output.
+async fn send_edit(room: &Joined, event_id: &OwnedEventId, body: &str) {
+    let mut content = RoomMessageEventContent::text_markdown(body);
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        event_id.clone(),
+        Box::new(RoomMessageEventContent::text_markdown(body).into()),
+    )));
+
+    room.send(content, None).await.map_err(|e| {
+        error!("Failed to send streamed edit: {:?}", e);
+    }).ok();
+}
+
 /// Joining rooms on invite.
 async fn on_stripped_state_member(
     room_member: StrippedRoomMemberEvent,
@@ -187,39 +330,116 @@ async fn room_event_to_chatgpt_request(
     let mut incoming_messages = room.messages(MessagesOptions::backward()).await?.chunk;
     incoming_messages.reverse();
 
-    let mut messages = vec![];
+    let room_settings = commands::room_settings(room).await;
+
+    // `!reset` hides everything up to and including the reset command itself,
+    // so the room starts from a clean slate without losing its history on disk.
+    if let Some(ref reset_after) = room_settings.reset_after {
+        if let Some(position) = incoming_messages.iter().position(|event| {
+            event
+                .event
+                .get_field::<OwnedEventId>("event_id")
+                .ok()
+                .flatten()
+                .as_deref()
+                == Some(reset_after.as_ref())
+        }) {
+            incoming_messages.drain(..=position);
+        }
+    }
+
+    let mut original_events = vec![];
 
     for event in incoming_messages {
-        if let AnyTimelineEvent::MessageLike(event) = event.event.deserialize()? {
-            if let AnyMessageLikeEvent::RoomMessage(event) = event {
-                if let MessageLikeEvent::Original(event) = event {
-                    if let MessageType::Text(ref text_content) = event.content.msgtype {
-                        messages.push(ChatCompletionRequestMessage {
-                            role: match client.user_id() {
-                                Some(user_id) if user_id == event.sender => Role::Assistant,
-                                _ => Role::User,
-                            },
-                            content: text_content.body.to_string(),
-                            name: None,
-                            // name: Some(event.sender.to_string()), // TODO: figure out why setting the name breaks the request
-                        });
-                    }
-                }
+        if let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+            MessageLikeEvent::Original(event),
+        )) = event.event.deserialize()?
+        {
+            original_events.push(event);
+        }
+    }
+
+    // Streaming (chunk0-4) sends a placeholder message and then edits it in
+    // place via `m.replace` events. Neither the placeholder's initial "…"
+    // body nor the intermediate edits are a real conversation turn, so drop
+    // the edit events themselves and give each placeholder the body of its
+    // latest edit — otherwise a single streamed reply shows up as several
+    // near-duplicate assistant turns in the next request's history.
+    let mut replaced_bodies: HashMap<OwnedEventId, String> = HashMap::new();
+
+    for event in &original_events {
+        if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+            if let MessageType::Text(ref text_content) = replacement.new_content.msgtype {
+                replaced_bodies.insert(replacement.event_id.clone(), text_content.body.clone());
             }
         }
     }
 
+    let mut history = vec![];
+
+    for event in original_events {
+        if matches!(event.content.relates_to, Some(Relation::Replacement(_))) {
+            continue;
+        }
+
+        if let MessageType::Text(ref text_content) = event.content.msgtype {
+            let body = replaced_bodies
+                .get(&event.event_id)
+                .unwrap_or(&text_content.body);
+
+            history.push(ChatCompletionRequestMessage {
+                role: match client.user_id() {
+                    Some(user_id) if user_id == event.sender => Role::Assistant,
+                    _ => Role::User,
+                },
+                content: body.to_string(),
+                name: None,
+                // name: Some(event.sender.to_string()), // TODO: figure out why setting the name breaks the request
+            });
+        }
+    }
+
+    let reserved_completion_tokens = tokens::reserved_completion_tokens();
+    let system_prompt_tokens = room_settings
+        .system_prompt
+        .as_deref()
+        .map(tokens::estimate_tokens)
+        .unwrap_or(0);
+
+    // The pinned system prompt and the reserved completion both eat into the
+    // same context window as history, so both must come off the budget
+    // before we decide how many history messages fit.
+    let history_budget = tokens::model_context_tokens()
+        .saturating_sub(reserved_completion_tokens)
+        .saturating_sub(system_prompt_tokens);
+
+    let windowed_history = tokens::window_history(history, history_budget);
+
+    let mut messages = vec![];
+
+    if let Some(system_prompt) = room_settings.system_prompt {
+        messages.push(ChatCompletionRequestMessage {
+            role: Role::System,
+            content: system_prompt,
+            name: None,
+        });
+    }
+
+    messages.extend(windowed_history);
+
     debug!("Creating ChatGPT request for messages: {:?}", messages);
 
     Ok(CreateChatCompletionRequest {
-        model: "gpt-3.5-turbo".into(),
+        model: room_settings.model.unwrap_or_else(|| "gpt-3.5-turbo".into()),
         messages,
-        temperature: None,
+        temperature: room_settings.temperature,
         top_p: None,
         n: Some(1),
         stream: Some(false),
         stop: None,
-        max_tokens: None,
+        max_tokens: Some(
+            u16::try_from(reserved_completion_tokens).unwrap_or(u16::MAX),
+        ),
         presence_penalty: None,
         frequency_penalty: None,
         logit_bias: None,
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use matrix_sdk::Session;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// On-disk representation of everything we need to resume a session without
+/// logging in again, modeled on the `TomlConfig { session }` pattern used by
+/// the matrix-bot examples.
+#[derive(Debug, Serialize, Deserialize)]
+struct TomlConfig {
+    session: Session,
+}
+
+/// Loads a previously persisted [`Session`] from `path`, if one exists.
+pub async fn load_session(path: impl AsRef<Path>) -> Result<Option<Session>> {
+    let path = path.as_ref();
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read session file at {}", path.display()))?;
+    let config: TomlConfig = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse session file at {}", path.display()))?;
+
+    Ok(Some(config.session))
+}
+
+/// Persists `session` to `path`, creating parent directories as needed.
+///
+/// The file holds a long-lived access token, so on Unix it's created with
+/// `0600` permissions (owner read/write only) regardless of umask.
+pub async fn save_session(path: impl AsRef<Path>, session: Session) -> Result<()> {
+    let path = path.as_ref();
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent).await?;
+        }
+    }
+
+    let config = TomlConfig { session };
+    let contents = toml::to_string_pretty(&config)?;
+
+    let mut open_options = fs::OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    open_options.mode(0o600);
+
+    let mut file = open_options
+        .open(path)
+        .await
+        .with_context(|| format!("failed to open session file at {}", path.display()))?;
+
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
+        .await
+        .with_context(|| format!("failed to set permissions on {}", path.display()))?;
+
+    file.write_all(contents.as_bytes())
+        .await
+        .with_context(|| format!("failed to write session file at {}", path.display()))?;
+
+    Ok(())
+}
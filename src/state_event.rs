@@ -0,0 +1,28 @@
+use matrix_sdk::{
+    room::Room,
+    ruma::events::{macros::EventContent, EmptyStateKey, SyncOrStrippedState},
+};
+use serde::{Deserialize, Serialize};
+
+/// Per-room ChatGPT configuration, stored server-side as room state so it
+/// survives bot restarts and syncs automatically to every device.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "rs.matrix-chatgpt.config", kind = State, state_key_type = EmptyStateKey)]
+pub struct ChatGptConfigEventContent {
+    pub system_prompt: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+}
+
+/// Reads back the persisted configuration for `room`, if any has been set.
+pub async fn load_room_config(room: &Room) -> Option<ChatGptConfigEventContent> {
+    let raw_event = room
+        .get_state_event_static::<ChatGptConfigEventContent>()
+        .await
+        .ok()??;
+
+    match raw_event.deserialize().ok()? {
+        SyncOrStrippedState::Sync(event) => event.as_original().map(|event| event.content.clone()),
+        SyncOrStrippedState::Stripped(event) => Some(event.content.clone()),
+    }
+}
@@ -0,0 +1,121 @@
+use std::env;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use tracing::warn;
+
+const CHARS_PER_TOKEN: usize = 4;
+const PER_MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+/// A rough, tiktoken-free estimate of how many tokens `text` will cost: the
+/// chars-per-token rule of thumb OpenAI documents, plus a small per-message
+/// overhead for the role/name wrapper.
+pub fn estimate_tokens(text: &str) -> usize {
+    PER_MESSAGE_OVERHEAD_TOKENS + text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Total context window of the configured model, in tokens.
+pub fn model_context_tokens() -> usize {
+    env::var("MODEL_CONTEXT_TOKENS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(4096)
+}
+
+/// Tokens reserved for the completion itself. Subtracted from
+/// [`model_context_tokens`] to get the history budget.
+///
+/// Clamped to `u16::MAX` since it's ultimately sent as `max_tokens`, which
+/// the OpenAI API accepts as a 16-bit value.
+pub fn reserved_completion_tokens() -> usize {
+    let configured = env::var("RESERVED_COMPLETION_TOKENS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1024);
+
+    if configured > u16::MAX as usize {
+        warn!(
+            "RESERVED_COMPLETION_TOKENS={} exceeds max_tokens' limit of {}; clamping",
+            configured,
+            u16::MAX
+        );
+        u16::MAX as usize
+    } else {
+        configured
+    }
+}
+
+/// Selects as many of the most recent `history` messages as fit within
+/// `budget` tokens. Walks newest-first so a too-large budget overrun only
+/// ever drops the oldest messages, then restores chronological order.
+pub fn window_history(
+    history: Vec<ChatCompletionRequestMessage>,
+    budget: usize,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut windowed = vec![];
+    let mut tokens_used = 0;
+
+    for message in history.into_iter().rev() {
+        let message_tokens = estimate_tokens(&message.content);
+
+        if tokens_used + message_tokens > budget {
+            break;
+        }
+
+        tokens_used += message_tokens;
+        windowed.push(message);
+    }
+
+    windowed.reverse();
+    windowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_message(content: &str) -> ChatCompletionRequestMessage {
+        ChatCompletionRequestMessage {
+            role: async_openai::types::Role::User,
+            content: content.to_string(),
+            name: None,
+        }
+    }
+
+    #[test]
+    fn estimate_tokens_counts_overhead_and_chars() {
+        assert_eq!(estimate_tokens(""), PER_MESSAGE_OVERHEAD_TOKENS);
+        assert_eq!(estimate_tokens("abcd"), PER_MESSAGE_OVERHEAD_TOKENS + 1);
+        assert_eq!(estimate_tokens("abcde"), PER_MESSAGE_OVERHEAD_TOKENS + 2);
+    }
+
+    #[test]
+    fn window_history_keeps_most_recent_messages_within_budget() {
+        // Oldest to newest, like the history vec built in `main.rs` before windowing.
+        let history = vec![
+            user_message(&"a".repeat(40)),
+            user_message(&"b".repeat(40)),
+            user_message(&"c".repeat(40)),
+        ];
+        let budget =
+            estimate_tokens(&history[1].content) + estimate_tokens(&history[2].content);
+
+        let windowed = window_history(history.clone(), budget);
+        let windowed_contents: Vec<&str> =
+            windowed.iter().map(|message| message.content.as_str()).collect();
+
+        assert_eq!(
+            windowed_contents,
+            vec![history[1].content.as_str(), history[2].content.as_str()]
+        );
+    }
+
+    #[test]
+    fn window_history_drops_everything_when_budget_is_too_small() {
+        let history = vec![user_message(&"x".repeat(100))];
+        let budget = estimate_tokens(&history[0].content) - 1;
+
+        let windowed = window_history(history, budget);
+
+        assert!(windowed.is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use futures_util::StreamExt;
+use matrix_sdk::{
+    encryption::verification::{SasState, Verification},
+    ruma::events::key::verification::{
+        request::ToDeviceKeyVerificationRequestEvent, start::ToDeviceKeyVerificationStartEvent,
+    },
+    Client as MatrixClient,
+};
+use tracing::{info, warn};
+
+/// Auto-accepts incoming key-verification requests from the bot's own other
+/// devices so its messages are trusted without a manual ceremony.
+pub async fn on_device_key_verification_request(
+    event: ToDeviceKeyVerificationRequestEvent,
+    client: MatrixClient,
+) {
+    if client.user_id() != Some(&event.sender) {
+        // Only auto-verify our own other devices, never a third party.
+        return;
+    }
+
+    let Some(request) = client
+        .encryption()
+        .get_verification_request(&event.sender, &event.content.transaction_id)
+        .await
+    else {
+        return;
+    };
+
+    if let Err(err) = request.accept().await {
+        warn!("Failed to accept verification request: {:?}", err);
+    }
+}
+
+/// Drives a SAS verification flow to completion once the other device starts it.
+pub async fn on_device_key_verification_start(
+    event: ToDeviceKeyVerificationStartEvent,
+    client: MatrixClient,
+) {
+    if client.user_id() != Some(&event.sender) {
+        return;
+    }
+
+    let Some(Verification::SasV1(sas)) = client
+        .encryption()
+        .get_verification(&event.sender, event.content.transaction_id.as_str())
+        .await
+    else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        if let Err(err) = sas.accept().await {
+            warn!("Failed to accept SAS verification: {:?}", err);
+            return;
+        }
+
+        let mut changes = sas.changes();
+
+        while let Some(state) = changes.next().await {
+            match state {
+                SasState::KeysExchanged { .. } => {
+                    // We trust our own devices unconditionally; no emoji
+                    // comparison is needed for a headless bot.
+                    sas.confirm().await.ok();
+                }
+                SasState::Done { .. } => {
+                    info!(
+                        "Successfully verified device {}",
+                        sas.other_device().device_id()
+                    );
+                    break;
+                }
+                SasState::Cancelled(info) => {
+                    warn!("Verification was cancelled: {:?}", info.reason());
+                    break;
+                }
+                _ => {}
+            }
+        }
+    });
+}